@@ -1,211 +1,641 @@
 use std::collections::HashMap;
-use std::collections::hash_map::Entry;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::sync::Weak;
+use std::sync::Once;
 use std::thread::Builder;
-
-use futures::stream::Stream;
-use futures::task;
-use futures::task::Executor;
-use futures::task::Run;
+use std::time::Duration;
 
 use conversions::Sample;
 use cpal;
 use cpal::Endpoint;
 use cpal::EventLoop;
 use cpal::UnknownTypeBuffer;
-use cpal::Voice;
+use cpal::VoiceId;
 use dynamic_mixer;
 use source::Source;
 
+lazy_static! {
+    static ref ENGINE: Arc<Engine> = {
+        let events_loop = Arc::new(EventLoop::new());
+
+        let engine = Arc::new(Engine {
+            events_loop: events_loop,
+            voices: Mutex::new(HashMap::with_capacity(1)),
+            end_points: Mutex::new(HashMap::with_capacity(1)),
+            rt_promoted: Once::new(),
+        });
+
+        // We ignore errors when creating the background thread.
+        // The user won't get any audio, but that's better than a panic.
+        Builder::new()
+            .name("rodio audio processing".to_string())
+            .spawn({
+                let engine = engine.clone();
+                move || {
+                    let events_loop = engine.events_loop.clone();
+                    events_loop.run(move |voice_id, buffer| engine.fill_buffer(voice_id, buffer))
+                }
+            })
+            .ok()
+            .map(|jg| jg.thread().clone());
+
+        engine
+    };
+}
+
+/// Error that can happen when attaching a source to an endpoint.
+#[derive(Debug)]
+pub enum PlayError {
+    /// Failed to enumerate the endpoint's supported formats.
+    FormatsEnumeration(cpal::FormatsEnumerationError),
+    /// The endpoint doesn't expose any supported format.
+    NoSupportedFormat,
+    /// cpal failed to create a voice for the endpoint.
+    VoiceCreation(cpal::CreationError),
+}
+
+impl fmt::Display for PlayError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.description())
+    }
+}
+
+impl Error for PlayError {
+    fn description(&self) -> &str {
+        match *self {
+            PlayError::FormatsEnumeration(_) => "could not enumerate the endpoint's supported formats",
+            PlayError::NoSupportedFormat => "the endpoint doesn't support any format",
+            PlayError::VoiceCreation(_) => "could not create a voice for the endpoint",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            PlayError::FormatsEnumeration(ref err) => Some(err),
+            PlayError::NoSupportedFormat => None,
+            PlayError::VoiceCreation(ref err) => Some(err),
+        }
+    }
+}
+
+/// Plays a source to an end point until it ends, reporting any failure to
+/// attach it instead of panicking.
+///
+/// The playing uses a background thread.
+pub fn try_play_raw<S>(endpoint: &Endpoint, source: S) -> Result<(), PlayError>
+    where S: Source<Item = f32> + Send + 'static
+{
+    ENGINE.start(endpoint, source)
+}
+
 /// Plays a source to an end point until it ends.
 ///
 /// The playing uses a background thread.
+///
+/// Kept for backward compatibility: errors are logged to stderr and
+/// swallowed. Prefer [`try_play_raw`] in new code so failures (a
+/// disconnected device, an endpoint with no usable format, ...) can
+/// actually be handled instead of silently producing no audio.
 pub fn play_raw<S>(endpoint: &Endpoint, source: S)
     where S: Source<Item = f32> + Send + 'static
 {
-    lazy_static! {
-        static ref ENGINE: Engine = {
-            let events_loop = Arc::new(EventLoop::new());
-
-            // We ignore errors when creating the background thread.
-            // The user won't get any audio, but that's better than a panic.
-            Builder::new()
-                .name("rodio audio processing".to_string())
-                .spawn({
-                    let events_loop = events_loop.clone();
-                    move || {
-                        max_thread_priority();
-                        events_loop.run()
-                    }
-                })
-                .ok()
-                .map(|jg| jg.thread().clone());
-
-            Engine {
-                events_loop: events_loop,
-                end_points: Mutex::new(HashMap::with_capacity(1)),
+    if let Err(err) = try_play_raw(endpoint, source) {
+        eprintln!("rodio: failed to play source on endpoint {:?}: {}", endpoint.name(), err);
+    }
+}
+
+// Guards the one-time promotion of the background audio thread to a
+// real-time scheduling class. We can't do this at thread-spawn time any
+// more, because the callback period depends on the format of the first
+// voice that gets created, which isn't known until a voice is built.
+fn promote_current_thread_once(once: &Once, period: Duration) {
+    once.call_once(|| rt_priority::promote(period));
+}
+
+// Platform-specific real-time promotion of the calling thread.
+//
+// Unlike a plain "max priority" bump, this asks the OS to schedule the
+// audio thread deterministically for the lifetime of one callback period,
+// which is what actually avoids glitches under system load.
+mod rt_priority {
+    use std::time::Duration;
+
+    /// Promotes the calling thread to a real-time scheduling class suitable
+    /// for processing one audio callback every `period`.
+    ///
+    /// `period` should be `buffer_frames / samples_rate` seconds, i.e. how
+    /// often the audio callback is expected to run. Only the calling thread
+    /// is affected; call this from the thread that will run the callback.
+    pub fn promote(period: Duration) {
+        imp::promote(period);
+    }
+
+    #[cfg(target_os = "linux")]
+    mod imp {
+        use std::time::Duration;
+
+        use dbus::arg::messageitem::MessageItem;
+        use dbus::ffidisp::{BusType, Connection};
+        use dbus::Message;
+        use libc::{getrlimit, setrlimit, rlimit, RLIMIT_RTTIME};
+
+        // Before asking RtKit, raise our own RLIMIT_RTTIME soft limit to at
+        // least four callback periods (in microseconds). A limit that is
+        // already higher is left as it is; it is never lowered here.
+        fn raise_rttime_limit(period: Duration) {
+            let wanted = (period.as_secs() * 1_000_000 + period.subsec_micros() as u64) * 4;
+            let mut limit = rlimit { rlim_cur: 0, rlim_max: 0 };
+            unsafe {
+                if getrlimit(RLIMIT_RTTIME, &mut limit) != 0 {
+                    return;
+                }
+                limit.rlim_cur = wanted.max(limit.rlim_cur);
+                limit.rlim_max = limit.rlim_cur.max(limit.rlim_max);
+                let _ = setrlimit(RLIMIT_RTTIME, &limit);
             }
+        }
+
+        pub fn promote(period: Duration) {
+            raise_rttime_limit(period);
+
+            let connection = match Connection::get_private(BusType::System) {
+                Ok(c) => c,
+                Err(err) => {
+                    eprintln!("Unable to reach RtKit over D-Bus: {:?}", err);
+                    return;
+                }
+            };
+
+            let tid = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+            let pid = unsafe { libc::getpid() } as i32;
+
+            let mut msg = match Message::new_method_call(
+                "org.freedesktop.RealtimeKit1",
+                "/org/freedesktop/RealtimeKit1",
+                "org.freedesktop.RealtimeKit1",
+                "MakeThreadRealtimeWithPID",
+            ) {
+                Ok(m) => m,
+                Err(err) => {
+                    eprintln!("Unable to build RtKit request: {}", err);
+                    return;
+                }
+            };
+            msg.append_items(&[
+                MessageItem::UInt64(pid as u64),
+                MessageItem::UInt64(tid as u64),
+                MessageItem::UInt32(RT_PRIORITY),
+            ]);
+
+            if let Err(err) = connection.send_with_reply_and_block(msg, 1000) {
+                eprintln!("RtKit refused to make the audio thread real-time: {:?}", err);
+            }
+        }
+
+        // A conservative priority: high enough to preempt normal desktop
+        // work, low enough to leave room for the kernel's own RT tasks.
+        const RT_PRIORITY: u32 = 10;
+    }
+
+    #[cfg(target_os = "macos")]
+    mod imp {
+        use std::time::Duration;
+
+        use mach::kern_return::KERN_SUCCESS;
+        use mach::mach_time::mach_timebase_info;
+        use mach::thread_policy::{
+            thread_policy_set,
+            thread_time_constraint_policy,
+            THREAD_TIME_CONSTRAINT_POLICY,
+            THREAD_TIME_CONSTRAINT_POLICY_COUNT,
         };
+        use mach::thread_act::mach_thread_self;
+
+        pub fn promote(period: Duration) {
+            let mut timebase = mach_timebase_info { numer: 0, denom: 0 };
+            unsafe { mach_timebase_info(&mut timebase) };
+            if timebase.numer == 0 || timebase.denom == 0 {
+                eprintln!("Unable to read mach timebase, not promoting audio thread");
+                return;
+            }
+
+            let period_ns = period.as_secs() * 1_000_000_000 + period.subsec_nanos() as u64;
+            let to_ticks = |ns: u64| (ns * timebase.denom as u64 / timebase.numer as u64) as u32;
+
+            // We ask for three quarters of the period for computation (this
+            // is the audio callback doing real work) and to be scheduled
+            // again no later than the end of the period.
+            let policy = thread_time_constraint_policy {
+                period: to_ticks(period_ns),
+                computation: to_ticks(period_ns * 3 / 4),
+                constraint: to_ticks(period_ns),
+                preemptible: 1,
+            };
+
+            let result = unsafe {
+                thread_policy_set(
+                    mach_thread_self(),
+                    THREAD_TIME_CONSTRAINT_POLICY,
+                    &policy as *const _ as *mut _,
+                    THREAD_TIME_CONSTRAINT_POLICY_COUNT,
+                )
+            };
+            if result != KERN_SUCCESS {
+                eprintln!("thread_policy_set failed with code {}", result);
+            }
+        }
     }
 
-    ENGINE.start(endpoint, source);
-}
+    #[cfg(windows)]
+    mod imp {
+        use std::time::Duration;
 
-#[cfg(not(windows))]
-fn max_thread_priority() {
-    use thread_priority::{
-        set_thread_priority,
-        thread_native_id,
-        ThreadPriority,
-        ThreadSchedulePolicy,
-        NormalThreadSchedulePolicy,
-    };
+        use winapi::um::avrt::AvSetMmThreadCharacteristicsA;
+        use std::ffi::CString;
+
+        pub fn promote(_period: Duration) {
+            let task_name = CString::new("Pro Audio").unwrap();
+            let mut task_index: u32 = 0;
+            let handle = unsafe {
+                AvSetMmThreadCharacteristicsA(task_name.as_ptr(), &mut task_index)
+            };
+            if handle.is_null() {
+                eprintln!("AvSetMmThreadCharacteristics failed to promote the audio thread.");
+            }
+            // The handle is intentionally leaked: MMCSS reverts the
+            // thread's characteristics automatically when the thread exits,
+            // and we have no natural point at which to call
+            // `AvRevertMmThreadCharacteristics` from here.
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    mod imp {
+        use std::time::Duration;
 
-    let result = set_thread_priority(
-        thread_native_id(),
-        ThreadPriority::Max,
-        ThreadSchedulePolicy::Normal(NormalThreadSchedulePolicy::Normal)
-    );
-    if let Err(err) = result {
-        eprintln!("Unable to set thread priority for audio engine: {:?}", err);
+        pub fn promote(_period: Duration) {
+            // No real-time promotion strategy for this platform; fall back
+            // to whatever scheduling priority the thread already has.
+        }
     }
 }
 
-#[cfg(windows)]
-fn max_thread_priority() {
-    use kernel32::{
-        SetThreadPriority,
-        GetCurrentThread,
-    };
-    use winapi::winbase::THREAD_PRIORITY_TIME_CRITICAL;
-    unsafe {
-        if SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_TIME_CRITICAL as i32) == 0 {
-            eprintln!("Unable to set thread priority for audio engine.");
+// Wraps a source to keep an external counter in sync with how many sources
+// are actually live inside a mixer. We can't rely on the mixer's output
+// going quiet (`None`) to mean "no sources left": a mixer may well keep
+// yielding `Some(0.0)` forever once empty, to avoid ever producing an
+// underrun, in which case the callback would never see a drain. Counting
+// sources in and decrementing on drop (i.e. once the mixer itself is done
+// with it and discards it) gives `Engine::fill_buffer` an idle signal that
+// doesn't depend on that choice.
+struct TrackSourceCount<S> {
+    inner: S,
+    count: Arc<AtomicUsize>,
+}
+
+impl<S> TrackSourceCount<S> {
+    fn new(inner: S, count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        TrackSourceCount {
+            inner: inner,
+            count: count,
         }
     }
 }
 
+impl<S> Drop for TrackSourceCount<S> {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S> Iterator for TrackSourceCount<S>
+    where S: Iterator
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for TrackSourceCount<S>
+    where S: Source
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn samples_rate(&self) -> u32 {
+        self.inner.samples_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
 // The internal engine of this library.
 //
-// Each `Engine` owns a thread that runs in the background and plays the audio.
+// A single background thread runs `events_loop.run(...)` for the lifetime
+// of the process; every voice, across every endpoint, is fed from that one
+// callback instead of each voice driving its own task.
 struct Engine {
-    // The events loop which the voices are created with.
+    // The events loop which the voices are created with, and which also
+    // drives the shared callback below.
     events_loop: Arc<EventLoop>,
 
+    // Per-voice state needed to answer the events loop's data callback,
+    // looked up by the `VoiceId` cpal passes in.
+    voices: Mutex<HashMap<VoiceId, VoiceState>>,
+
     // TODO: don't use the endpoint name, as it's slow
-    end_points: Mutex<HashMap<String, Weak<dynamic_mixer::DynamicMixerController<f32>>>>,
+    end_points: Mutex<HashMap<String, EndPointState>>,
+
+    // Ensures the background thread is only promoted to a real-time
+    // scheduling class once, the first time its callback period becomes
+    // known.
+    rt_promoted: Once,
 }
 
-impl Engine {
-    // Builds a new sink that targets a given endpoint.
-    fn start<S>(&self, endpoint: &Endpoint, source: S)
-        where S: Source<Item = f32> + Send + 'static
-    {
-        let mut voice_to_start = None;
-
-        let mixer = {
-            let mut end_points = self.end_points.lock().unwrap();
-
-            match end_points.entry(endpoint.name()) {
-                Entry::Vacant(e) => {
-                    let (mixer, voice) = new_voice(endpoint, &self.events_loop);
-                    e.insert(Arc::downgrade(&mixer));
-                    voice_to_start = Some(voice);
-                    mixer
-                },
-                Entry::Occupied(mut e) => {
-                    if let Some(m) = e.get().upgrade() {
-                        m.clone()
-                    } else {
-                        let (mixer, voice) = new_voice(endpoint, &self.events_loop);
-                        e.insert(Arc::downgrade(&mixer));
-                        voice_to_start = Some(voice);
-                        mixer
-                    }
-                },
-            }
-        };
+// The consumer side of one voice's mixer, plus the bits the shared callback
+// needs to compute the real-time period and to decide when to pause.
+struct VoiceState {
+    mixer_rx: dynamic_mixer::DynamicMixer<f32>,
+    samples_rate: u32,
+    channels: usize,
+    // Set to `true` by `Engine::start` whenever a source is added, and back
+    // to `false` from `Engine::fill_buffer` once every source has drained
+    // out of the mixer. Shared with the matching `EndPointState`.
+    active: Arc<AtomicBool>,
+    // How many sources `TrackSourceCount` currently considers live in this
+    // mixer. The idle decision is based on this reaching zero, not on the
+    // mixer's sample output going quiet.
+    source_count: Arc<AtomicUsize>,
+}
+
+// Everything the engine keeps alive for one endpoint. The voice's id is
+// kept here so we can pause and resume it without tearing down and
+// reopening the device every time a source merely drains out and another
+// arrives for the same endpoint.
+//
+// `mixer` is a genuine strong `Arc`, not a `Weak`: the matching
+// `VoiceState.mixer_rx` already holds a strong reference of its own for as
+// long as the voice exists, so a `Weak` here could never be told apart from
+// one the engine keeps alive itself. Whether an endpoint is idle is decided
+// purely from `active`/`source_count` below, which the engine tracks
+// independently of the mixer's own reference count.
+struct EndPointState {
+    voice_id: VoiceId,
+    mixer: Arc<dynamic_mixer::DynamicMixerController<f32>>,
+    active: Arc<AtomicBool>,
+    source_count: Arc<AtomicUsize>,
+}
 
-        mixer.add(source);
+impl Engine {
+    // Sweeps every endpoint other than `keep` whose voice has gone idle (no
+    // sources left and nothing currently playing), fully destroying them and
+    // releasing their OS device handles. Without this, an endpoint that
+    // simply finishes playing (and is never targeted again) would sit there
+    // paused, holding its device open forever: the resume path in `start`
+    // below only wakes an endpoint when a *new* source happens to target
+    // that same endpoint, which is why `keep` is spared.
+    fn reap_idle_end_points(&self, end_points: &mut HashMap<String, EndPointState>, keep: &str) {
+        let dead: Vec<String> = end_points.iter()
+            .filter(|&(name, state)| {
+                name.as_str() != keep &&
+                    !state.active.load(Ordering::Relaxed) && state.source_count.load(Ordering::Relaxed) == 0
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
 
-        if let Some(mut voice) = voice_to_start {
-            voice.play();
+        for name in dead {
+            if let Some(state) = end_points.remove(&name) {
+                self.events_loop.destroy_voice(state.voice_id);
+                self.voices.lock().unwrap().remove(&state.voice_id);
+            }
         }
     }
-}
 
-// TODO: handle possible errors here
-fn new_voice(endpoint: &Endpoint, events_loop: &Arc<EventLoop>)
-             -> (Arc<dynamic_mixer::DynamicMixerController<f32>>, Voice) {
-    // Determine the format to use for the new voice.
-    let format = endpoint
-        .supported_formats()
-        .unwrap()
-        .fold(None, |f1, f2| {
-            if f1.is_none() {
-                return Some(f2);
-            }
+    // Builds a new sink that targets a given endpoint.
+    fn start<S>(&self, endpoint: &Endpoint, source: S) -> Result<(), PlayError>
+        where S: Source<Item = f32> + Send + 'static
+    {
+        let mut end_points = self.end_points.lock().unwrap();
 
-            let f1 = f1.unwrap();
+        self.reap_idle_end_points(&mut end_points, &endpoint.name());
 
-            // We privilege f32 formats to avoid a conversion.
-            if f2.data_type == cpal::SampleFormat::F32 && f1.data_type != cpal::SampleFormat::F32 {
-                return Some(f2);
-            }
+        // Reaping just above destroyed every other endpoint that had gone
+        // idle, but spared the one being targeted: if it is still in the
+        // map it is either currently playing or merely paused waiting for
+        // more sources to arrive on the *same* voice. So an endpoint needs
+        // a fresh voice exactly when it isn't here at all.
+        if !end_points.contains_key(&endpoint.name()) {
+            // Query the source before it's moved into the mixer so the new
+            // voice can be opened at a format that matches it, instead of
+            // always going through the generic heuristic.
+            let source_channels = source.channels();
+            let source_rate = source.samples_rate();
 
-            // Do not go below 44100 if possible.
-            if f1.samples_rate.0 < 44100 {
-                return Some(f2);
-            }
+            let (mixer, voice_id, active, source_count) = new_voice(self, endpoint, source_channels, source_rate)?;
+            mixer.add(TrackSourceCount::new(source, source_count.clone()));
+            active.store(true, Ordering::Relaxed);
+            self.events_loop.play(&voice_id);
 
-            // Priviledge outputs with 2 channels for now.
-            if f2.channels.len() == 2 && f1.channels.len() != 2 {
-                return Some(f2);
+            end_points.insert(endpoint.name(), EndPointState {
+                voice_id: voice_id,
+                mixer: mixer,
+                active: active,
+                source_count: source_count,
+            });
+        } else {
+            // The endpoint's voice (and its format) is already decided and
+            // shared with whatever else is currently playing on it. If this
+            // source's rate or channel count doesn't match, the mixer
+            // resamples/remixes it on the fly rather than reopening the
+            // device; only the first source to reach an idle endpoint gets
+            // to pick a bit-accurate format for it.
+            let state = end_points.get_mut(&endpoint.name()).unwrap();
+            state.mixer.add(TrackSourceCount::new(source, state.source_count.clone()));
+
+            if !state.active.swap(true, Ordering::Relaxed) {
+                // The voice had been paused because its mixer ran out of
+                // sources; wake it back up now that one has arrived.
+                self.events_loop.play(&state.voice_id);
             }
+        }
 
-            Some(f1)
-        })
-        .expect("The endpoint doesn't support any format!?");
+        Ok(())
+    }
 
-    let (voice, stream) = Voice::new(&endpoint, &format, events_loop).unwrap();
+    // The single callback that drives every voice on every endpoint. cpal
+    // calls this on the background thread each time a voice needs its
+    // buffer filled; we look the voice up by id and pull samples from its
+    // mixer, regardless of how many other voices exist.
+    fn fill_buffer(&self, voice_id: VoiceId, mut buffer: UnknownTypeBuffer) {
+        // The real-time promotion of the background thread is deferred
+        // until here, because only now do we know how large a buffer the
+        // device actually asked for (and therefore the real callback
+        // period), and only now are we running on the thread that needs
+        // promoting. Look up just the format, then drop the lock before
+        // promoting: on Linux this can block on a D-Bus round trip to
+        // RtKit for up to a second, and that must never happen while
+        // `voices` is held, or every other voice's callback and any
+        // concurrent `start()` would stall behind it.
+        let format = {
+            let voices = self.voices.lock().unwrap();
+            voices.get(&voice_id).map(|voice| (voice.channels, voice.samples_rate))
+        };
+        if let Some((channels, samples_rate)) = format {
+            let frames = buffer_sample_count(&buffer) / channels;
+            let rate = samples_rate.max(1) as u64;
+            let period = Duration::new(frames as u64 / rate, ((frames as u64 % rate) * 1_000_000_000 / rate) as u32);
+            promote_current_thread_once(&self.rt_promoted, period);
+        }
 
-    let (mixer_tx, mut mixer_rx) = {
-        dynamic_mixer::mixer::<f32>(format.channels.len() as u16, format.samples_rate.0)
-    };
+        let mut voices = self.voices.lock().unwrap();
+        let voice = match voices.get_mut(&voice_id) {
+            Some(voice) => voice,
+            // The voice was destroyed concurrently with this callback
+            // firing; nothing to fill.
+            None => return,
+        };
 
-    let future_to_exec = stream.for_each(move |mut buffer| -> Result<_, ()> {
         match buffer {
             UnknownTypeBuffer::U16(ref mut buffer) => {
                 for d in buffer.iter_mut() {
-                    *d = mixer_rx.next().map(|s| s.to_u16()).unwrap_or(0u16);
+                    *d = voice.mixer_rx.next().map(|s| s.to_u16()).unwrap_or(0u16);
                 }
             },
             UnknownTypeBuffer::I16(ref mut buffer) => {
                 for d in buffer.iter_mut() {
-                    *d = mixer_rx.next().map(|s| s.to_i16()).unwrap_or(0i16);
+                    *d = voice.mixer_rx.next().map(|s| s.to_i16()).unwrap_or(0i16);
                 }
             },
             UnknownTypeBuffer::F32(ref mut buffer) => {
                 for d in buffer.iter_mut() {
-                    *d = mixer_rx.next().unwrap_or(0f32);
+                    *d = voice.mixer_rx.next().unwrap_or(0f32);
                 }
             },
         };
 
-        Ok(())
+        // The mixer has no live sources left: stop the callback from
+        // running until `Engine::start` attaches a new one and wakes it
+        // back up. This is based on `source_count`, not on the mixer's
+        // sample output going quiet, because a mixer with no sources may
+        // still keep yielding `Some(0.0)` forever to avoid an underrun.
+        if voice.source_count.load(Ordering::Relaxed) == 0 && voice.active.swap(false, Ordering::Relaxed) {
+            self.events_loop.pause(&voice_id);
+        }
+    }
+}
+
+fn new_voice(engine: &Engine, endpoint: &Endpoint, source_channels: u16, source_rate: u32)
+             -> Result<(Arc<dynamic_mixer::DynamicMixerController<f32>>, VoiceId, Arc<AtomicBool>, Arc<AtomicUsize>), PlayError> {
+    let formats: Vec<_> = endpoint
+        .supported_formats()
+        .map_err(PlayError::FormatsEnumeration)?
+        .collect();
+
+    // Prefer a format that already matches what the source produces, so the
+    // common single-source case opens the device at a bit-accurate rate and
+    // channel count instead of going through the mixer's resampler. Only
+    // fall back to the generic heuristic below when nothing close enough
+    // is available.
+    let format = closest_matching_format(formats.iter().cloned(), source_channels, source_rate)
+        .or_else(|| {
+            formats.into_iter().fold(None, |f1, f2| {
+                if f1.is_none() {
+                    return Some(f2);
+                }
+
+                let f1 = f1.unwrap();
+
+                // We privilege f32 formats to avoid a conversion.
+                if f2.data_type == cpal::SampleFormat::F32 && f1.data_type != cpal::SampleFormat::F32 {
+                    return Some(f2);
+                }
+
+                // Do not go below 44100 if possible.
+                if f1.samples_rate.0 < 44100 {
+                    return Some(f2);
+                }
+
+                // Priviledge outputs with 2 channels for now.
+                if f2.channels.len() == 2 && f1.channels.len() != 2 {
+                    return Some(f2);
+                }
+
+                Some(f1)
+            })
+        })
+        .ok_or(PlayError::NoSupportedFormat)?;
+
+    let voice_id = engine.events_loop
+        .build_voice(endpoint, &format)
+        .map_err(PlayError::VoiceCreation)?;
+
+    let (mixer_tx, mixer_rx) = {
+        dynamic_mixer::mixer::<f32>(format.channels.len() as u16, format.samples_rate.0)
+    };
+
+    let active = Arc::new(AtomicBool::new(false));
+    let source_count = Arc::new(AtomicUsize::new(0));
+
+    engine.voices.lock().unwrap().insert(voice_id, VoiceState {
+        mixer_rx: mixer_rx,
+        samples_rate: format.samples_rate.0,
+        channels: format.channels.len().max(1),
+        active: active.clone(),
+        source_count: source_count.clone(),
     });
 
-    {
-        struct MyExecutor;
-        impl Executor for MyExecutor {
-            fn execute(&self, r: Run) {
-                r.run();
+    Ok((mixer_tx, voice_id, active, source_count))
+}
+
+// Picks, among `formats`, the one whose channel count matches
+// `source_channels` exactly and whose sample rate is closest to
+// `source_rate`, as long as that rate is within 1% of the source's (or
+// matches exactly). Returns `None` if no format clears that bar, so the
+// caller can fall back to its own heuristic.
+fn closest_matching_format<I>(formats: I, source_channels: u16, source_rate: u32) -> Option<cpal::Format>
+    where I: Iterator<Item = cpal::Format>
+{
+    let max_rate_distance = (source_rate / 100).max(1);
+
+    formats
+        .filter(|format| format.channels.len() as u16 == source_channels)
+        .filter_map(|format| {
+            let rate_distance = (format.samples_rate.0 as i64 - source_rate as i64).abs() as u32;
+            if rate_distance <= max_rate_distance {
+                Some((rate_distance, format))
+            } else {
+                None
             }
-        }
-        task::spawn(future_to_exec).execute(Arc::new(MyExecutor));
-    }
+        })
+        .min_by_key(|&(rate_distance, _)| rate_distance)
+        .map(|(_, format)| format)
+}
 
-    (mixer_tx, voice)
+// Returns how many samples (across all channels) a buffer holds, regardless
+// of its underlying sample type.
+fn buffer_sample_count(buffer: &UnknownTypeBuffer) -> usize {
+    match *buffer {
+        UnknownTypeBuffer::U16(ref b) => b.len(),
+        UnknownTypeBuffer::I16(ref b) => b.len(),
+        UnknownTypeBuffer::F32(ref b) => b.len(),
+    }
 }